@@ -0,0 +1,69 @@
+// SPDX-FileCopyrightText: 2021 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+use num_derive::FromPrimitive;
+use solana_program::{decode_error::DecodeError, program_error::ProgramError};
+use thiserror::Error;
+
+#[derive(Clone, Debug, Eq, PartialEq, Error, FromPrimitive)]
+pub enum LidoError {
+    #[error("Account is not owned by the Lido program.")]
+    InvalidOwner,
+
+    #[error("The provided account does not hold a valid Lido instance.")]
+    InvalidLidoAccount,
+
+    #[error("The Lido account's on-chain layout version is not the one this instruction accepts: other instructions need the current version, the migration instruction an older one.")]
+    LidoVersionMismatch,
+
+    #[error("The serialized account list is corrupt or was truncated.")]
+    InvalidAccountListData,
+
+    #[error("The manager account does not match, or did not sign the transaction.")]
+    InvalidManager,
+
+    #[error("The provided account is not a valid stSOL token account.")]
+    InvalidStSolAccount,
+
+    #[error("One of the provided accounts does not match what was expected.")]
+    InvalidAccountInfo,
+
+    #[error("The provided stake account is not valid for this operation.")]
+    InvalidStakeAccount,
+
+    #[error("These two stake accounts cannot be merged.")]
+    StakeAccountsNotMergeable,
+
+    #[error("Commission must be a percentage, between 0 and 100.")]
+    ValidationCommissionOutOfBounds,
+
+    #[error("Minimum vote success rate must be a percentage, between 0 and 100.")]
+    ValidationVoteSuccessRateOutOfBounds,
+
+    #[error("Minimum block production rate must be between 0 and 100.")]
+    ValidationBlockProductionRateOutOfBounds,
+
+    #[error("Stake-target weights must each be a percentage, and must sum to 100.")]
+    ValidationStakeWeightsOutOfBounds,
+
+    #[error("This validator is still active; deactivate it before removing it.")]
+    ValidatorIsStillActive,
+
+    #[error("This validator still has stake accounts delegated to it.")]
+    ValidatorHasUndelegatedStakeAccounts,
+
+    #[error("The account is too small to hold its entries at the current layout size; reallocate it before writing to the list.")]
+    AccountListTooSmallForMigration,
+}
+
+impl From<LidoError> for ProgramError {
+    fn from(e: LidoError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+impl<T> DecodeError<T> for LidoError {
+    fn type_of() -> &'static str {
+        "LidoError"
+    }
+}
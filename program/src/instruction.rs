@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: 2021 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+use solana_program::{account_info::AccountInfo, program_error::ProgramError};
+
+/// Declare an `*InfoV2`-style struct that borrows a fixed number of
+/// accounts, in order, out of the raw account slice an instruction
+/// receives. This is the shape every processor function in
+/// `process_management` expects its accounts in.
+macro_rules! accounts_struct {
+    ($name:ident { $( $field:ident ),+ $(,)? }) => {
+        pub struct $name<'a, 'b> {
+            $( pub $field: &'a AccountInfo<'b>, )+
+        }
+
+        impl<'a, 'b> $name<'a, 'b> {
+            pub fn try_from_slice(accounts_raw: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+                match accounts_raw {
+                    [ $( $field ),+ ] => Ok(Self { $( $field ),+ }),
+                    _ => Err(ProgramError::NotEnoughAccountKeys),
+                }
+            }
+        }
+    };
+}
+
+accounts_struct! {
+    ChangeRewardDistributionInfo {
+        lido,
+        manager,
+        treasury_account,
+        developer_account,
+    }
+}
+
+accounts_struct! {
+    AddValidatorInfoV2 {
+        lido,
+        manager,
+        validator_vote_account,
+        validator_list,
+    }
+}
+
+accounts_struct! {
+    RemoveValidatorInfoV2 {
+        lido,
+        validator_list,
+        validator_vote_account_to_remove,
+    }
+}
+
+accounts_struct! {
+    DeactivateValidatorInfoV2 {
+        lido,
+        manager,
+        validator_list,
+        validator_vote_account_to_deactivate,
+    }
+}
+
+accounts_struct! {
+    DeactivateIfViolatesInfo {
+        lido,
+        validator_perf_list,
+        validator_list,
+        validator_vote_account_to_deactivate,
+    }
+}
+
+/// Accounts for `DeactivateIfViolatesBatch`. Unlike the other `*Info`
+/// structs, the validator's vote accounts are not a fixed set: one is
+/// needed per validator in the swept window, so they are taken as the
+/// remainder of `accounts_raw` after the fixed accounts.
+pub struct DeactivateIfViolatesBatchInfo<'a, 'b> {
+    pub lido: &'a AccountInfo<'b>,
+    pub validator_perf_list: &'a AccountInfo<'b>,
+    pub validator_list: &'a AccountInfo<'b>,
+    pub vote_accounts: &'a [AccountInfo<'b>],
+}
+
+impl<'a, 'b> DeactivateIfViolatesBatchInfo<'a, 'b> {
+    pub fn try_from_slice(accounts_raw: &'a [AccountInfo<'b>]) -> Result<Self, ProgramError> {
+        match accounts_raw {
+            [lido, validator_perf_list, validator_list, vote_accounts @ ..] => Ok(Self {
+                lido,
+                validator_perf_list,
+                validator_list,
+                vote_accounts,
+            }),
+            _ => Err(ProgramError::NotEnoughAccountKeys),
+        }
+    }
+}
+
+accounts_struct! {
+    AddMaintainerInfoV2 {
+        lido,
+        manager,
+        maintainer_list,
+        maintainer,
+    }
+}
+
+accounts_struct! {
+    RemoveMaintainerInfoV2 {
+        lido,
+        manager,
+        maintainer_list,
+        maintainer,
+    }
+}
+
+accounts_struct! {
+    ChangeCriteriaInfo {
+        lido,
+        manager,
+    }
+}
+
+/// Accounts for `MigrateLidoVersion`. `payer` and `system_program` are only
+/// needed when the migrated layout is larger than the account's current
+/// size: they fund and perform the realloc that makes room for it.
+accounts_struct! {
+    MigrateLidoVersionInfo {
+        lido,
+        manager,
+        payer,
+        system_program,
+    }
+}
+
+accounts_struct! {
+    ChangeStakeWeightsInfo {
+        lido,
+        manager,
+    }
+}
+
+accounts_struct! {
+    MergeStakeInfoV2 {
+        lido,
+        validator_list,
+        validator_vote_account,
+        from_stake,
+        to_stake,
+        stake_authority,
+        sysvar_clock,
+        stake_history,
+        stake_program,
+    }
+}
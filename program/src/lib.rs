@@ -0,0 +1,15 @@
+// SPDX-FileCopyrightText: 2021 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+pub mod error;
+pub mod instruction;
+pub mod logic;
+pub mod process_management;
+pub mod processor;
+pub mod stake_target;
+pub mod state;
+pub mod vote_state;
+
+/// Seed used to derive the PDA that is the withdraw/stake authority over
+/// every stake account Solido controls.
+pub const STAKE_AUTHORITY: &[u8] = b"stake_authority";
@@ -0,0 +1,16 @@
+// SPDX-FileCopyrightText: 2021 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, msg, rent::Rent};
+
+use crate::error::LidoError;
+
+/// Confirm that `account` holds enough lamports to be rent-exempt,
+/// `account_name` is used only to make the error message useful.
+pub fn check_rent_exempt(rent: &Rent, account: &AccountInfo, account_name: &str) -> ProgramResult {
+    if !rent.is_exempt(account.lamports(), account.data_len()) {
+        msg!("{} is not rent-exempt.", account_name);
+        return Err(LidoError::InvalidAccountInfo.into());
+    }
+    Ok(())
+}
@@ -1,20 +1,27 @@
 // SPDX-FileCopyrightText: 2021 Chorus One AG
 // SPDX-License-Identifier: GPL-3.0
 
-use solana_program::program::invoke_signed;
+use borsh::BorshSerialize;
+use solana_program::clock::Clock;
+use solana_program::program::{invoke, invoke_signed};
 use solana_program::rent::Rent;
+use solana_program::stake::state::StakeState;
+use solana_program::system_instruction;
 use solana_program::sysvar::Sysvar;
 use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, msg, pubkey::Pubkey};
 
 use crate::logic::check_rent_exempt;
 use crate::processor::StakeType;
-use crate::state::{Criteria, Lido, ValidatorPerf};
+use crate::stake_target;
+use crate::stake_target::StakeWeights;
+use crate::state::{Criteria, Lido, ValidatorPerf, LIDO_VERSION};
 use crate::vote_state::PartialVoteState;
 use crate::{
     error::LidoError,
     instruction::{
         AddMaintainerInfoV2, AddValidatorInfoV2, ChangeCriteriaInfo, ChangeRewardDistributionInfo,
-        DeactivateIfViolatesInfo, DeactivateValidatorInfoV2, MergeStakeInfoV2,
+        ChangeStakeWeightsInfo, DeactivateIfViolatesBatchInfo, DeactivateIfViolatesInfo,
+        DeactivateValidatorInfoV2, MergeStakeInfoV2, MigrateLidoVersionInfo,
         RemoveMaintainerInfoV2, RemoveValidatorInfoV2,
     },
     state::{ListEntry, Maintainer, RewardDistribution, Validator},
@@ -191,10 +198,15 @@ pub fn process_deactivate_if_violates(
         let data = accounts.validator_vote_account_to_deactivate.data.borrow();
         let commission = get_vote_account_commission(&data)?;
 
-        // Check if the validator violates the criteria.
+        // Check if the validator violates the criteria. A performance score
+        // of zero is treated the same as violating a threshold: it is a
+        // hard deactivation, not just a reduced stake target.
         let does_perform_well =
             validator_perf.map_or(true, |perf| perf.meets_criteria(&lido.criteria));
         let does_perform_well = does_perform_well && commission <= lido.criteria.max_commission;
+        let does_perform_well = does_perform_well
+            && validator_perf
+                .map_or(true, |perf| stake_target::validator_score(perf, &lido.criteria) > 0.0);
 
         // If the validator does not perform well, deactivate it.
         !does_perform_well
@@ -212,6 +224,109 @@ pub fn process_deactivate_if_violates(
     Ok(())
 }
 
+/// Deactivate every violating validator in `[start_index, start_index +
+/// count)` of the validator list, in a single instruction.
+///
+/// `DeactivateIfViolates` handles one validator per call, so keeping a large
+/// list clean requires one transaction per validator. This sweeps a window
+/// of the list instead: each validator's vote account must be passed in
+/// `accounts.vote_accounts`, in list order, matching the window. Already
+/// inactive validators are skipped, so sweeping the same window twice is a
+/// no-op, and a maintainer can freely split a large list into several
+/// transactions by varying `start_index` and `count` to stay within compute
+/// limits.
+pub fn process_deactivate_if_violates_batch(
+    program_id: &Pubkey,
+    start_index: u32,
+    count: u32,
+    accounts_raw: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = DeactivateIfViolatesBatchInfo::try_from_slice(accounts_raw)?;
+    let lido = Lido::deserialize_lido(program_id, accounts.lido)?;
+
+    let validator_perf_list_data = &mut *accounts.validator_perf_list.data.borrow_mut();
+    let validator_perfs = lido.deserialize_account_list_info::<ValidatorPerf>(
+        program_id,
+        accounts.validator_perf_list,
+        validator_perf_list_data,
+    )?;
+
+    let validator_list_data = &mut *accounts.validator_list.data.borrow_mut();
+    let mut validators = lido.deserialize_account_list_info::<Validator>(
+        program_id,
+        accounts.validator_list,
+        validator_list_data,
+    )?;
+
+    let start_index = start_index as usize;
+    let end_index = start_index
+        .checked_add(count as usize)
+        .ok_or(LidoError::InvalidAccountInfo)?
+        .min(validators.len());
+    if start_index > end_index {
+        msg!(
+            "start_index {} is past the end of the validator list.",
+            start_index
+        );
+        return Err(LidoError::InvalidAccountInfo.into());
+    }
+
+    let window = validators[start_index..end_index].iter_mut();
+    if window.len() != accounts.vote_accounts.len() {
+        msg!(
+            "Expected {} vote accounts for this window, got {}.",
+            window.len(),
+            accounts.vote_accounts.len()
+        );
+        return Err(LidoError::InvalidAccountInfo.into());
+    }
+
+    for (validator, vote_account) in window.zip(accounts.vote_accounts.iter()) {
+        if vote_account.key != validator.pubkey() {
+            msg!(
+                "Vote account {} does not match validator {} at this position.",
+                vote_account.key,
+                validator.pubkey()
+            );
+            return Err(LidoError::InvalidAccountInfo.into());
+        }
+
+        // Nothing to do if the validator is already inactive.
+        if !validator.active {
+            continue;
+        }
+
+        let should_deactivate = if vote_account.owner == &solana_program::vote::program::id() {
+            let validator_perf = validator_perfs
+                .iter()
+                .find(|perf| &perf.validator_vote_account_address == vote_account.key);
+
+            let data = vote_account.data.borrow();
+            let commission = get_vote_account_commission(&data)?;
+
+            let does_perform_well =
+                validator_perf.map_or(true, |perf| perf.meets_criteria(&lido.criteria));
+            let does_perform_well = does_perform_well && commission <= lido.criteria.max_commission;
+            let does_perform_well = does_perform_well
+                && validator_perf
+                    .map_or(true, |perf| stake_target::validator_score(perf, &lido.criteria) > 0.0);
+
+            !does_perform_well
+        } else {
+            // The vote account is closed by node operator.
+            true
+        };
+        if !should_deactivate {
+            continue;
+        }
+
+        validator.deactivate();
+        msg!("Validator {} deactivated.", validator.pubkey());
+    }
+
+    Ok(())
+}
+
 /// Adds a maintainer to the list of maintainers
 pub fn process_add_maintainer(program_id: &Pubkey, accounts_raw: &[AccountInfo]) -> ProgramResult {
     let accounts = AddMaintainerInfoV2::try_from_slice(accounts_raw)?;
@@ -249,8 +364,18 @@ pub fn process_remove_maintainer(
     Ok(())
 }
 
-/// Set the new curation criteria. If validators exceed those thresholds,
+/// Set the new curation thresholds. If validators exceed those thresholds,
 /// they will be deactivated by `DeactivateIfViolates`.
+///
+/// This only ever touches the threshold fields of `Criteria`
+/// (`max_commission`, `min_vote_success_rate`, `min_block_production_rate`,
+/// `block_production_rate_cap`); the stake-target weights are a separate
+/// concern owned by `process_change_stake_weights`, which is also the only
+/// place that validates they sum to 100. `new_criteria` is accepted as a
+/// full `Criteria` for convenience, but its weight fields are ignored so a
+/// caller that only means to touch thresholds cannot accidentally zero out
+/// the weights and make every validator's `stake_target::validator_score`
+/// collapse to 0.
 pub fn process_change_criteria(
     program_id: &Pubkey,
     new_criteria: Criteria,
@@ -259,13 +384,105 @@ pub fn process_change_criteria(
     if new_criteria.max_commission > 100 {
         return Err(LidoError::ValidationCommissionOutOfBounds.into());
     }
+    if new_criteria.min_vote_success_rate > 100 {
+        return Err(LidoError::ValidationVoteSuccessRateOutOfBounds.into());
+    }
+    if new_criteria.min_block_production_rate > 100 {
+        return Err(LidoError::ValidationBlockProductionRateOutOfBounds.into());
+    }
 
     let accounts = ChangeCriteriaInfo::try_from_slice(accounts_raw)?;
     let mut lido = Lido::deserialize_lido(program_id, accounts.lido)?;
 
     lido.check_manager(accounts.manager)?;
 
-    lido.criteria = new_criteria;
+    lido.criteria = Criteria {
+        weight_commission: lido.criteria.weight_commission,
+        weight_vote_success_rate: lido.criteria.weight_vote_success_rate,
+        weight_block_production_rate: lido.criteria.weight_block_production_rate,
+        ..new_criteria
+    };
+
+    lido.save(accounts.lido)
+}
+
+/// Upgrade a `Lido` account still on an older on-chain layout to the
+/// current one. `Lido::deserialize_lido` refuses to touch an account on a
+/// mismatched layout version, so this must run before any other
+/// instruction can act on the account again. If the new layout is larger
+/// than the account's current allocation, `payer` tops up the account's
+/// rent-exempt balance and the account is resized to fit.
+pub fn process_migrate_lido_version(
+    program_id: &Pubkey,
+    accounts_raw: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = MigrateLidoVersionInfo::try_from_slice(accounts_raw)?;
+
+    if accounts.lido.owner != program_id {
+        return Err(LidoError::InvalidOwner.into());
+    }
+    let stored_version = *accounts
+        .lido
+        .data
+        .borrow()
+        .first()
+        .ok_or(LidoError::InvalidLidoAccount)?;
+    if stored_version == LIDO_VERSION {
+        msg!("Lido account is already on layout version {}.", LIDO_VERSION);
+        return Err(LidoError::LidoVersionMismatch.into());
+    }
+
+    let migrated = Lido::migrate(stored_version, &accounts.lido.data.borrow())?;
+    migrated.check_manager(accounts.manager)?;
+
+    let new_len = migrated
+        .try_to_vec()
+        .map_err(|_| LidoError::InvalidLidoAccount)?
+        .len();
+    if accounts.lido.data_len() < new_len {
+        let rent = &Rent::get()?;
+        let new_minimum_balance = rent.minimum_balance(new_len);
+        let lamports_needed = new_minimum_balance.saturating_sub(accounts.lido.lamports());
+        if lamports_needed > 0 {
+            invoke(
+                &system_instruction::transfer(accounts.payer.key, accounts.lido.key, lamports_needed),
+                &[
+                    accounts.payer.clone(),
+                    accounts.lido.clone(),
+                    accounts.system_program.clone(),
+                ],
+            )?;
+        }
+        accounts.lido.realloc(new_len, false)?;
+    }
+
+    migrated.save(accounts.lido)
+}
+
+/// Set the weights used to combine a validator's commission, vote success
+/// rate and block production rate into the performance score that
+/// `stake_target::compute_target_stake_fractions` allocates stake with.
+pub fn process_change_stake_weights(
+    program_id: &Pubkey,
+    new_weights: StakeWeights,
+    accounts_raw: &[AccountInfo],
+) -> ProgramResult {
+    if new_weights.commission as u16
+        + new_weights.vote_success_rate as u16
+        + new_weights.block_production_rate as u16
+        != 100
+    {
+        return Err(LidoError::ValidationStakeWeightsOutOfBounds.into());
+    }
+
+    let accounts = ChangeStakeWeightsInfo::try_from_slice(accounts_raw)?;
+    let mut lido = Lido::deserialize_lido(program_id, accounts.lido)?;
+
+    lido.check_manager(accounts.manager)?;
+
+    lido.criteria.weight_commission = new_weights.commission;
+    lido.criteria.weight_vote_success_rate = new_weights.vote_success_rate;
+    lido.criteria.weight_block_production_rate = new_weights.block_production_rate;
 
     lido.save(accounts.lido)
 }
@@ -277,6 +494,19 @@ pub fn process_change_criteria(
 /// exist and is merged with the stake defined by `stake_accounts_seed_begin +
 /// 1`, and `stake_accounts_seed_begin` is incremented by one.
 /// All fully active stake accounts precede the activating stake accounts.
+///
+/// A `from_stake` that was created this epoch and is merged away within the
+/// same epoch ("merge-into-last") is derived from this epoch's
+/// `StakeType::Transient` generation rather than the long-lived
+/// seed-derived address. This matters because
+/// `stake_seeds.begin` identifies an account only by its seed: once an
+/// account is merged away, its address is deterministic and anybody could
+/// fund it again at the same seed, "reviving" an account the protocol
+/// believes no longer exists. Tagging the address with the epoch means
+/// replaying a past epoch's seed produces a different address, so a
+/// resurrected transient account never matches what this instruction
+/// expects to find. A fully active `from_stake`, and every `to_stake`, lives
+/// at its plain, epoch-independent `StakeType::Stake` address.
 pub fn process_merge_stake(
     program_id: &Pubkey,
     validator_index: u32,
@@ -284,6 +514,7 @@ pub fn process_merge_stake(
 ) -> ProgramResult {
     let accounts = MergeStakeInfoV2::try_from_slice(accounts_raw)?;
     let lido = Lido::deserialize_lido(program_id, accounts.lido)?;
+    let clock = Clock::from_account_info(accounts.sysvar_clock)?;
 
     let validator_list_data = &mut *accounts.validator_list.data.borrow_mut();
     let mut validator = lido.deserialize_account_list_info::<Validator>(
@@ -303,12 +534,55 @@ pub fn process_merge_stake(
         return Err(LidoError::InvalidStakeAccount.into());
     }
 
-    // Recalculate the `from_stake`.
+    // A merge is only ever valid in two shapes: either `from_stake` was just
+    // created this epoch and is being folded straight into `to_stake`
+    // ("merge-into-last"), or both accounts have fully activated in a prior
+    // epoch. "Fully active" is judged by activation epoch alone: an account
+    // that started activating in any earlier epoch counts as fully active,
+    // whether or not its warmup has completed. The only other shape, an
+    // activation epoch after the current one, is rejected below.
+    let from_activation_epoch = stake_activation_epoch(accounts.from_stake)?;
+    let is_merge_into_last = from_activation_epoch == clock.epoch;
+    let is_fully_active = from_activation_epoch < clock.epoch;
+    if !is_merge_into_last && !is_fully_active {
+        msg!(
+            "Stake account for seed {} has an activation epoch after the current epoch; refusing to merge it.",
+            from_seed
+        );
+        return Err(LidoError::InvalidStakeAccount.into());
+    }
+
+    // `from_stake`'s activation shape alone is not enough: the stake program
+    // merges a this-epoch/prior-epoch pair just as opaquely as two
+    // incompatible validators, so `to_stake` must be checked the same way.
+    // Require both accounts to be in the same shape (both still activating
+    // this epoch, or both fully active) before calling the pair mergeable.
+    let to_activation_epoch = stake_activation_epoch(accounts.to_stake)?;
+    let to_is_merge_into_last = to_activation_epoch == clock.epoch;
+    let to_is_fully_active = to_activation_epoch < clock.epoch;
+    if is_merge_into_last != to_is_merge_into_last || is_fully_active != to_is_fully_active {
+        msg!(
+            "Stake account for seed {} and seed {} have mismatched activation states; refusing to merge them.",
+            from_seed,
+            to_seed,
+        );
+        return Err(LidoError::StakeAccountsNotMergeable.into());
+    }
+
+    // Recalculate `from_stake`: an account created and merged away within
+    // this epoch lives in this epoch's transient generation, a fully active
+    // one at its long-lived seed-derived address.
+    let from_stake_type = if is_merge_into_last {
+        StakeType::Transient
+    } else {
+        StakeType::Stake
+    };
     let (from_stake_addr, _) = validator.find_stake_account_address(
         program_id,
         accounts.lido.key,
         from_seed,
-        StakeType::Stake,
+        from_stake_type,
+        from_activation_epoch,
     );
     // Compare with the stake passed in `accounts`.
     if &from_stake_addr != accounts.from_stake.key {
@@ -325,6 +599,7 @@ pub fn process_merge_stake(
         accounts.lido.key,
         to_seed,
         StakeType::Stake,
+        clock.epoch,
     );
     if &to_stake_addr != accounts.to_stake.key {
         msg!(
@@ -335,6 +610,19 @@ pub fn process_merge_stake(
         );
         return Err(LidoError::InvalidStakeAccount.into());
     }
+
+    // The stake program itself refuses to merge accounts whose delegation
+    // or `credits_observed` are incompatible, and which combinations are
+    // accepted depends on which runtime features are active. Check
+    // mergeability ourselves first, so an incompatible pair comes back as a
+    // clear `StakeAccountsNotMergeable` error instead of an opaque CPI
+    // failure, and so that `begin` is never advanced when no merge happened.
+    check_stake_accounts_mergeable(accounts.from_stake, accounts.to_stake, is_fully_active)?;
+
+    // Only once both addresses are confirmed to match their expected
+    // derivation, and the accounts are confirmed mergeable, do we advance
+    // `begin`: every check above returns early on failure, so `begin` never
+    // advances past an account this instruction did not actually merge.
     validator.stake_seeds.begin += 1;
     // Merge `from_stake_addr` to `to_stake_addr`, at the end of the
     // instruction, `from_stake_addr` ceases to exist.
@@ -368,3 +656,59 @@ pub fn process_merge_stake(
 
     Ok(())
 }
+
+/// Return the epoch at which `stake_account` started activating.
+///
+/// Used to tell apart a stake account that was just created this epoch
+/// (and must therefore be addressed through this epoch's transient
+/// generation) from one that has had at least one full epoch to activate.
+fn stake_activation_epoch(stake_account: &AccountInfo) -> Result<solana_program::clock::Epoch, LidoError> {
+    let stake_state = StakeState::deserialize(&stake_account.data.borrow())
+        .map_err(|_| LidoError::InvalidStakeAccount)?;
+    stake_state
+        .delegation()
+        .map(|delegation| delegation.activation_epoch)
+        .ok_or(LidoError::InvalidStakeAccount)
+}
+
+/// Check that the stake program would actually accept merging `from_stake`
+/// into `to_stake`, without relying on the CPI itself to tell us: they must
+/// be delegated to the same validator.
+///
+/// `credits_observed` is only required to match when `from_stake` is still
+/// activating (the merge-into-last case): the stake program cannot take a
+/// weighted average of rewards for an account that hasn't earned a full
+/// epoch's worth of them yet. Once `from_stake` has fully activated
+/// (`both_fully_active`), the stake program merges `credits_observed` as a
+/// stake-weighted average of the two accounts, so a mismatch there is
+/// expected in steady state rather than a sign the accounts are
+/// incompatible.
+fn check_stake_accounts_mergeable(
+    from_stake: &AccountInfo,
+    to_stake: &AccountInfo,
+    both_fully_active: bool,
+) -> Result<(), LidoError> {
+    let from_state =
+        StakeState::deserialize(&from_stake.data.borrow()).map_err(|_| LidoError::InvalidStakeAccount)?;
+    let to_state =
+        StakeState::deserialize(&to_stake.data.borrow()).map_err(|_| LidoError::InvalidStakeAccount)?;
+
+    let from_stake_data = from_state.stake().ok_or(LidoError::StakeAccountsNotMergeable)?;
+    let to_stake_data = to_state.stake().ok_or(LidoError::StakeAccountsNotMergeable)?;
+
+    if from_stake_data.delegation.voter_pubkey != to_stake_data.delegation.voter_pubkey {
+        msg!("Cannot merge stake accounts delegated to different validators.");
+        return Err(LidoError::StakeAccountsNotMergeable);
+    }
+
+    if !both_fully_active && from_stake_data.credits_observed != to_stake_data.credits_observed {
+        msg!(
+            "Cannot merge stake accounts with different credits_observed ({} vs {}).",
+            from_stake_data.credits_observed,
+            to_stake_data.credits_observed,
+        );
+        return Err(LidoError::StakeAccountsNotMergeable);
+    }
+
+    Ok(())
+}
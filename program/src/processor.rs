@@ -0,0 +1,19 @@
+// SPDX-FileCopyrightText: 2021 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Which generation of derived address a stake account belongs to.
+///
+/// `Stake` is the long-lived, seed-only derivation used for every stake
+/// account that is not merged away in the epoch it was created in. `Transient` is used
+/// for an account that is created and merged away within the same epoch;
+/// it is additionally tagged with the epoch it was created in, so an
+/// address from a past epoch can never be replayed to "revive" an account
+/// the protocol believes no longer exists. See
+/// `Validator::find_stake_account_address` and `process_merge_stake`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum StakeType {
+    Stake,
+    Transient,
+}
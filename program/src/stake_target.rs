@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2021 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+//! Performance-weighted stake-target allocation.
+//!
+//! Rather than spreading stake equally across all active validators, and
+//! letting curation be a strict active/inactive decision, this module turns
+//! a validator's commission, vote success rate and block production rate
+//! into a target stake fraction: well-performing validators are allocated
+//! proportionally more stake, and laggards are throttled down well before
+//! they are fully deactivated by `process_deactivate_if_violates`.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::pubkey::Pubkey;
+
+use crate::state::{Criteria, ListEntry, Validator, ValidatorPerf};
+
+/// The relative weights `w_c`, `w_v`, `w_b` used to combine a validator's
+/// commission, vote success rate and block production rate into a single
+/// performance score. Stored on `Criteria`, set through
+/// `process_change_stake_weights`. See `validator_score`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct StakeWeights {
+    pub commission: u8,
+    pub vote_success_rate: u8,
+    pub block_production_rate: u8,
+}
+
+/// The per-validator performance score, in `[0, 1]`, where `0` means the
+/// validator should receive no new stake at all and is a candidate for hard
+/// deactivation, and `1` is a perfect score on every metric.
+pub(crate) fn validator_score(perf: &ValidatorPerf, criteria: &Criteria) -> f64 {
+    let commission_component = if criteria.max_commission == 0 {
+        0.0
+    } else {
+        1.0 - (perf.commission as f64 / criteria.max_commission as f64)
+    };
+
+    let vote_component = perf.vote_success_rate as f64 / 100.0;
+
+    let block_component = if criteria.block_production_rate_cap == 0 {
+        0.0
+    } else {
+        perf.block_production_rate as f64 / criteria.block_production_rate_cap as f64
+    };
+
+    // `weight_*` are percentages that sum to 100 (enforced by
+    // `process_change_stake_weights`), so dividing by 100 here turns them
+    // into the fractional weights the weighted sum below needs. Without
+    // this, the weights themselves (which sum to 100, not 1) multiply the
+    // components, and the score saturates to 1.0 for almost every
+    // validator.
+    let score = criteria.weight_commission as f64 / 100.0 * commission_component.clamp(0.0, 1.0)
+        + criteria.weight_vote_success_rate as f64 / 100.0 * vote_component.clamp(0.0, 1.0)
+        + criteria.weight_block_production_rate as f64 / 100.0 * block_component.clamp(0.0, 1.0);
+
+    score.clamp(0.0, 1.0)
+}
+
+/// Compute the target stake fraction of every active validator, given its
+/// performance metrics.
+///
+/// A validator without a `ValidatorPerf` entry, or whose score is `0.0`,
+/// gets a target fraction of `0.0`. A score of `0.0` also makes
+/// `process_deactivate_if_violates` deactivate the validator; a missing
+/// `ValidatorPerf` entry does not. The staking/unstaking maintenance flow
+/// reads the fraction straight from here, whether or not that has happened.
+pub fn compute_target_stake_fractions(
+    validators: &[Validator],
+    perfs: &[ValidatorPerf],
+    criteria: &Criteria,
+) -> Vec<(Pubkey, f64)> {
+    let scores: Vec<(Pubkey, f64)> = validators
+        .iter()
+        .filter(|validator| validator.active)
+        .map(|validator| {
+            let score = perfs
+                .iter()
+                .find(|perf| perf.validator_vote_account_address == *validator.pubkey())
+                .map_or(0.0, |perf| validator_score(perf, criteria));
+            (*validator.pubkey(), score)
+        })
+        .collect();
+
+    let total_score: f64 = scores.iter().map(|(_, score)| score).sum();
+    if total_score == 0.0 {
+        return scores
+            .into_iter()
+            .map(|(pubkey, _)| (pubkey, 0.0))
+            .collect();
+    }
+
+    scores
+        .into_iter()
+        .map(|(pubkey, score)| (pubkey, score / total_score))
+        .collect()
+}
@@ -0,0 +1,529 @@
+// SPDX-FileCopyrightText: 2021 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+use std::ops::{Deref, DerefMut};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::clock::Epoch;
+use solana_program::program_error::ProgramError;
+use solana_program::program_pack::Pack;
+use solana_program::{account_info::AccountInfo, entrypoint::ProgramResult, msg, pubkey::Pubkey};
+
+use crate::error::LidoError;
+use crate::processor::StakeType;
+
+/// On-chain layout version of `Lido`. Bump this whenever a field is added
+/// to, or removed from, `Lido` or anything it embeds by value (such as
+/// `Criteria`), so `deserialize_lido` can tell an account that still has
+/// the old layout apart from one a maintainer has already migrated.
+pub const LIDO_VERSION: u8 = 2;
+
+/// The curation thresholds a validator must meet to stay active, and the
+/// weights used to turn its performance into a stake-target score. See
+/// `crate::stake_target`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Criteria {
+    pub max_commission: u8,
+    pub min_vote_success_rate: u8,
+    pub min_block_production_rate: u8,
+    /// Upper bound on `ValidatorPerf::block_production_rate`, used to scale
+    /// it into a `[0, 1]` component in `stake_target::validator_score`.
+    pub block_production_rate_cap: u8,
+    pub weight_commission: u8,
+    pub weight_vote_success_rate: u8,
+    pub weight_block_production_rate: u8,
+}
+
+/// A validator's fee recipients. Set through `ChangeRewardDistribution`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct FeeRecipients {
+    pub treasury_account: Pubkey,
+    pub developer_account: Pubkey,
+}
+
+/// How rewards are split between stakers, the treasury and the developer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct RewardDistribution {
+    pub treasury_fee: u32,
+    pub developer_fee: u32,
+    pub st_sol_appreciation: u32,
+}
+
+/// An entry of an `AccountList`, such as `Validator` or `Maintainer`.
+///
+/// `AccountList` stores entries back to back in a single account, each
+/// padded to `MAX_SIZE` bytes. `VERSION` lets it recognize, on
+/// deserialization, whether the bytes on chain still use an older layout
+/// (see `migrate`).
+pub trait ListEntry: BorshSerialize + BorshDeserialize + Default + Clone {
+    const VERSION: u8;
+    const MAX_SIZE: usize;
+
+    fn new(pubkey: Pubkey) -> Self;
+    fn pubkey(&self) -> &Pubkey;
+
+    /// The number of bytes an entry occupies when serialized under
+    /// `version`. Types whose layout has never changed can rely on the
+    /// default; `ValidatorPerf` overrides this for its older layout.
+    fn size_for_version(version: u8) -> usize {
+        let _ = version;
+        Self::MAX_SIZE
+    }
+
+    /// Reconstruct an entry that was serialized under an older `VERSION`.
+    /// The default assumes the layout never changed, so any mismatch is a
+    /// genuine data error; types whose layout did change override this.
+    fn migrate(old_version: u8, bytes: &[u8]) -> Result<Self, ProgramError> {
+        let _ = old_version;
+        Self::try_from_slice(bytes).map_err(|_| LidoError::InvalidAccountListData.into())
+    }
+}
+
+/// Per-validator performance metrics, reported by a maintainer and read by
+/// `meets_criteria` and `stake_target::validator_score`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ValidatorPerf {
+    pub validator_vote_account_address: Pubkey,
+    pub commission: u8,
+    /// Ratio of landed votes to total votes the validator was eligible to
+    /// cast, as a percentage.
+    pub vote_success_rate: u8,
+    /// Average blocks produced per minute during the validator's leader
+    /// slots.
+    pub block_production_rate: u8,
+}
+
+impl ValidatorPerf {
+    /// Whether these metrics satisfy `criteria`'s thresholds. Does not
+    /// consider commission, which `process_deactivate_if_violates` checks
+    /// directly against the live vote account instead of this cached copy.
+    pub fn meets_criteria(&self, criteria: &Criteria) -> bool {
+        self.vote_success_rate >= criteria.min_vote_success_rate
+            && self.block_production_rate >= criteria.min_block_production_rate
+    }
+}
+
+impl ListEntry for ValidatorPerf {
+    const VERSION: u8 = 2;
+    const MAX_SIZE: usize = 32 + 1 + 1 + 1;
+
+    fn new(pubkey: Pubkey) -> Self {
+        ValidatorPerf {
+            validator_vote_account_address: pubkey,
+            ..ValidatorPerf::default()
+        }
+    }
+
+    fn pubkey(&self) -> &Pubkey {
+        &self.validator_vote_account_address
+    }
+
+    fn size_for_version(version: u8) -> usize {
+        match version {
+            // Version 1 stored only the address and the commission; the
+            // rate fields did not exist yet.
+            1 => 32 + 1,
+            _ => Self::MAX_SIZE,
+        }
+    }
+
+    fn migrate(old_version: u8, bytes: &[u8]) -> Result<Self, ProgramError> {
+        match old_version {
+            1 => {
+                let validator_vote_account_address =
+                    Pubkey::try_from_slice(&bytes[0..32])
+                        .map_err(|_| LidoError::InvalidAccountListData)?;
+                let commission = bytes[32];
+                // A validator migrated from version 1 has no recorded rate
+                // history yet. Default to the maximum rate rather than 0,
+                // so it is not immediately flagged as violating the new
+                // performance thresholds before a maintainer has reported
+                // real numbers for it.
+                Ok(ValidatorPerf {
+                    validator_vote_account_address,
+                    commission,
+                    vote_success_rate: 100,
+                    block_production_rate: 100,
+                })
+            }
+            _ => Err(LidoError::InvalidAccountListData.into()),
+        }
+    }
+}
+
+/// The range of stake-account seeds `[begin, end)` currently in use for a
+/// validator; see `process_merge_stake`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct SeedRange {
+    pub begin: u64,
+    pub end: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Validator {
+    pub vote_account_address: Pubkey,
+    pub active: bool,
+    pub stake_seeds: SeedRange,
+    pub unstake_seeds: SeedRange,
+    pub stake_accounts_balance: u64,
+    pub unstake_accounts_balance: u64,
+}
+
+impl Validator {
+    /// Set the `active` flag to false. This is the only way a validator
+    /// leaves the active set; removal is a separate, later step.
+    pub fn deactivate(&mut self) {
+        self.active = false;
+    }
+
+    /// Whether this validator can be removed from the validator list:
+    /// it must be inactive, and have no stake delegated to it any more.
+    pub fn check_can_be_removed(&self) -> Result<(), LidoError> {
+        if self.active {
+            return Err(LidoError::ValidatorIsStillActive);
+        }
+        if self.stake_accounts_balance > 0 || self.unstake_accounts_balance > 0 {
+            return Err(LidoError::ValidatorHasUndelegatedStakeAccounts);
+        }
+        Ok(())
+    }
+
+    pub fn show_removed_error_msg(result: &Result<(), LidoError>) {
+        if let Err(error) = result {
+            msg!("Validator cannot be removed: {}", error);
+        }
+    }
+
+    /// Derive the stake-account address for `seed`.
+    ///
+    /// `StakeType::Stake` addresses are long-lived and keyed only by the
+    /// validator and the seed. `StakeType::Transient` addresses are also
+    /// tagged with `epoch`: a stake account created and merged away within
+    /// the same epoch must be addressed this way, so that funding the same
+    /// seed again in a later epoch derives a different address and cannot
+    /// be mistaken for the account that used to live there.
+    pub fn find_stake_account_address(
+        &self,
+        program_id: &Pubkey,
+        lido_address: &Pubkey,
+        seed: u64,
+        stake_type: StakeType,
+        epoch: Epoch,
+    ) -> (Pubkey, u8) {
+        let seed_suffix = match stake_type {
+            StakeType::Stake => format!("stake_account_{}", seed),
+            StakeType::Transient => format!("transient_stake_account_{}_{}", seed, epoch),
+        };
+        Pubkey::find_program_address(
+            &[
+                lido_address.as_ref(),
+                self.vote_account_address.as_ref(),
+                seed_suffix.as_bytes(),
+            ],
+            program_id,
+        )
+    }
+}
+
+impl ListEntry for Validator {
+    const VERSION: u8 = 1;
+    const MAX_SIZE: usize = 32 + 1 + 8 * 6;
+
+    fn new(pubkey: Pubkey) -> Self {
+        Validator {
+            vote_account_address: pubkey,
+            active: true,
+            ..Validator::default()
+        }
+    }
+
+    fn pubkey(&self) -> &Pubkey {
+        &self.vote_account_address
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Maintainer {
+    pub pubkey: Pubkey,
+}
+
+impl ListEntry for Maintainer {
+    const VERSION: u8 = 1;
+    const MAX_SIZE: usize = 32;
+
+    fn new(pubkey: Pubkey) -> Self {
+        Maintainer { pubkey }
+    }
+
+    fn pubkey(&self) -> &Pubkey {
+        &self.pubkey
+    }
+}
+
+/// A mutable, in-place view of an `AccountList` account's entries.
+///
+/// On deserialization, entries stored under an older `T::VERSION` are
+/// migrated to the current layout through `ListEntry::migrate`. When this
+/// value is dropped, all entries, including any mutation (`push`, `remove`,
+/// or a mutable borrow through `get_mut`/the `Deref` slice), are written
+/// back to `data` under the *current* version and size, whether or not
+/// anything changed, so an account is upgraded to the new layout the first
+/// time any instruction deserializes it after the program is upgraded.
+pub struct AccountList<'a, T> {
+    data: &'a mut [u8],
+    entries: Vec<T>,
+}
+
+const ACCOUNT_LIST_HEADER_LEN: usize = 1 + 4;
+
+impl<'a, T: ListEntry> AccountList<'a, T> {
+    pub fn deserialize_mut(data: &'a mut [u8]) -> Result<Self, ProgramError> {
+        if data.len() < ACCOUNT_LIST_HEADER_LEN {
+            return Err(LidoError::InvalidAccountListData.into());
+        }
+        let stored_version = data[0];
+        let length = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize;
+        let entry_size = T::size_for_version(stored_version);
+
+        let mut entries = Vec::with_capacity(length);
+        let mut offset = ACCOUNT_LIST_HEADER_LEN;
+        for _ in 0..length {
+            let chunk = data
+                .get(offset..offset + entry_size)
+                .ok_or(LidoError::InvalidAccountListData)?;
+            let entry = if stored_version == T::VERSION {
+                T::try_from_slice(chunk).map_err(|_| LidoError::InvalidAccountListData)?
+            } else {
+                T::migrate(stored_version, chunk)?
+            };
+            entries.push(entry);
+            offset += entry_size;
+        }
+
+        // `flush` always writes entries back at the *current* `T::MAX_SIZE`
+        // stride, even if they were read at an older, smaller one (that's
+        // how a migrated entry ends up occupying its new, larger size on
+        // chain). Nothing reallocates `data` to make room for that, so
+        // confirm up front that the existing buffer is already big enough;
+        // otherwise `flush` would index past the end of `data` and panic.
+        let required_len = ACCOUNT_LIST_HEADER_LEN + length * T::MAX_SIZE;
+        if data.len() < required_len {
+            msg!(
+                "Account is too small to hold {} entries at {} bytes each ({} bytes required, {} available); reallocate the account before migrating its contents.",
+                length,
+                T::MAX_SIZE,
+                required_len,
+                data.len(),
+            );
+            return Err(LidoError::AccountListTooSmallForMigration.into());
+        }
+
+        Ok(AccountList { data, entries })
+    }
+
+    fn check_index(&self, index: u32, expected_pubkey: &Pubkey) -> Result<usize, ProgramError> {
+        let index = index as usize;
+        match self.entries.get(index) {
+            Some(entry) if entry.pubkey() == expected_pubkey => Ok(index),
+            Some(_) => {
+                msg!("Entry at index {} does not match the expected account.", index);
+                Err(LidoError::InvalidAccountInfo.into())
+            }
+            None => {
+                msg!("Index {} is out of bounds.", index);
+                Err(LidoError::InvalidAccountInfo.into())
+            }
+        }
+    }
+
+    pub fn push(&mut self, entry: T) -> ProgramResult {
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: u32, expected_pubkey: &Pubkey) -> Result<T, ProgramError> {
+        let index = self.check_index(index, expected_pubkey)?;
+        Ok(self.entries.remove(index))
+    }
+
+    pub fn get_mut(&mut self, index: u32, expected_pubkey: &Pubkey) -> Result<&mut T, ProgramError> {
+        let index = self.check_index(index, expected_pubkey)?;
+        Ok(&mut self.entries[index])
+    }
+
+    fn flush(&mut self) {
+        self.data[0] = T::VERSION;
+        self.data[1..5].copy_from_slice(&(self.entries.len() as u32).to_le_bytes());
+
+        let mut offset = ACCOUNT_LIST_HEADER_LEN;
+        for entry in &self.entries {
+            let bytes = entry
+                .try_to_vec()
+                .expect("ListEntry serialization cannot fail.");
+            self.data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            offset += T::MAX_SIZE;
+        }
+    }
+}
+
+impl<'a, T: ListEntry> Deref for AccountList<'a, T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        &self.entries
+    }
+}
+
+impl<'a, T: ListEntry> DerefMut for AccountList<'a, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.entries
+    }
+}
+
+impl<'a, T: ListEntry> Drop for AccountList<'a, T> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Lido {
+    pub lido_version: u8,
+    pub manager: Pubkey,
+    pub st_sol_mint: Pubkey,
+    pub stake_authority_bump_seed: u8,
+    pub mint_authority_bump_seed: u8,
+    pub criteria: Criteria,
+    pub reward_distribution: RewardDistribution,
+    pub fee_recipients: FeeRecipients,
+}
+
+impl Lido {
+    pub fn deserialize_lido(
+        program_id: &Pubkey,
+        lido_account: &AccountInfo,
+    ) -> Result<Lido, ProgramError> {
+        if lido_account.owner != program_id {
+            return Err(LidoError::InvalidOwner.into());
+        }
+        // Check the layout version up front, from the raw byte rather than
+        // through a full `try_from_slice`: an account still on an older,
+        // differently-sized layout will not parse as the current `Lido` at
+        // all, so doing the version check only *after* a failed parse would
+        // make `LidoVersionMismatch` unreachable for exactly the accounts it
+        // exists to explain. `lido_version` is the first field in every
+        // layout this program has ever had, so reading it raw is safe.
+        let data = lido_account.data.borrow();
+        let stored_version = *data.first().ok_or(LidoError::InvalidLidoAccount)?;
+        if stored_version != LIDO_VERSION {
+            msg!(
+                "Lido account has layout version {}, expected {}; run the migration instruction first.",
+                stored_version,
+                LIDO_VERSION,
+            );
+            return Err(LidoError::LidoVersionMismatch.into());
+        }
+        let lido = Lido::try_from_slice(&data).map_err(|_| LidoError::InvalidLidoAccount)?;
+        Ok(lido)
+    }
+
+    /// Reconstruct a `Lido` account that was serialized under an older
+    /// `lido_version`, analogous to `ListEntry::migrate`. Used by
+    /// `process_migrate_lido_version` to upgrade an account still on an
+    /// earlier layout before any other instruction can touch it again.
+    pub fn migrate(old_version: u8, data: &[u8]) -> Result<Lido, ProgramError> {
+        match old_version {
+            // Version 1's `Criteria` held only `max_commission`; none of the
+            // rate thresholds or stake-target weights existed yet.
+            1 => {
+                let get = |range: std::ops::Range<usize>| {
+                    data.get(range).ok_or(LidoError::InvalidLidoAccount)
+                };
+                let manager = Pubkey::try_from_slice(get(1..33)?)
+                    .map_err(|_| LidoError::InvalidLidoAccount)?;
+                let st_sol_mint = Pubkey::try_from_slice(get(33..65)?)
+                    .map_err(|_| LidoError::InvalidLidoAccount)?;
+                let stake_authority_bump_seed = get(65..66)?[0];
+                let mint_authority_bump_seed = get(66..67)?[0];
+                let max_commission = get(67..68)?[0];
+                let treasury_fee =
+                    u32::from_le_bytes(get(68..72)?.try_into().expect("range has length 4"));
+                let developer_fee =
+                    u32::from_le_bytes(get(72..76)?.try_into().expect("range has length 4"));
+                let st_sol_appreciation =
+                    u32::from_le_bytes(get(76..80)?.try_into().expect("range has length 4"));
+                let treasury_account = Pubkey::try_from_slice(get(80..112)?)
+                    .map_err(|_| LidoError::InvalidLidoAccount)?;
+                let developer_account = Pubkey::try_from_slice(get(112..144)?)
+                    .map_err(|_| LidoError::InvalidLidoAccount)?;
+
+                Ok(Lido {
+                    lido_version: LIDO_VERSION,
+                    manager,
+                    st_sol_mint,
+                    stake_authority_bump_seed,
+                    mint_authority_bump_seed,
+                    criteria: Criteria {
+                        max_commission,
+                        // No threshold existed before; default to lenient
+                        // (0) rather than strict (100), so migrating does
+                        // not itself flag every validator as violating.
+                        min_vote_success_rate: 0,
+                        min_block_production_rate: 0,
+                        block_production_rate_cap: 100,
+                        // Put full weight on commission, none on the new
+                        // rate metrics, so `validator_score` reproduces the
+                        // pre-migration, commission-only ordering until a
+                        // manager deliberately calls
+                        // `process_change_stake_weights`.
+                        weight_commission: 100,
+                        weight_vote_success_rate: 0,
+                        weight_block_production_rate: 0,
+                    },
+                    reward_distribution: RewardDistribution {
+                        treasury_fee,
+                        developer_fee,
+                        st_sol_appreciation,
+                    },
+                    fee_recipients: FeeRecipients {
+                        treasury_account,
+                        developer_account,
+                    },
+                })
+            }
+            _ => Err(LidoError::InvalidLidoAccount.into()),
+        }
+    }
+
+    pub fn save(&self, lido_account: &AccountInfo) -> ProgramResult {
+        self.serialize(&mut *lido_account.data.borrow_mut())
+            .map_err(|_| LidoError::InvalidLidoAccount.into())
+    }
+
+    pub fn check_manager(&self, manager: &AccountInfo) -> ProgramResult {
+        if !manager.is_signer || &self.manager != manager.key {
+            return Err(LidoError::InvalidManager.into());
+        }
+        Ok(())
+    }
+
+    pub fn check_is_st_sol_account(&self, account: &AccountInfo) -> ProgramResult {
+        let token_account = spl_token::state::Account::unpack(&account.data.borrow())
+            .map_err(|_| LidoError::InvalidStSolAccount)?;
+        if token_account.mint != self.st_sol_mint {
+            return Err(LidoError::InvalidStSolAccount.into());
+        }
+        Ok(())
+    }
+
+    pub fn deserialize_account_list_info<'a, T: ListEntry>(
+        &self,
+        program_id: &Pubkey,
+        list_account: &AccountInfo,
+        list_data: &'a mut [u8],
+    ) -> Result<AccountList<'a, T>, ProgramError> {
+        if list_account.owner != program_id {
+            return Err(LidoError::InvalidOwner.into());
+        }
+        AccountList::<T>::deserialize_mut(list_data)
+    }
+}
@@ -0,0 +1,51 @@
+// SPDX-FileCopyrightText: 2021 Chorus One AG
+// SPDX-License-Identifier: GPL-3.0
+
+use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::error::LidoError;
+
+/// The fields of a vote account's `VoteState` that Solido cares about,
+/// without deserializing the full (and much larger) structure.
+pub struct PartialVoteState {
+    pub node_pubkey: Pubkey,
+    pub commission: u8,
+}
+
+impl PartialVoteState {
+    /// Deserialize `vote_account`, checking that it is owned by the vote
+    /// program and that its commission does not exceed `max_commission`.
+    pub fn deserialize(
+        vote_account: &AccountInfo,
+        max_commission: u8,
+    ) -> Result<PartialVoteState, ProgramError> {
+        if vote_account.owner != &solana_program::vote::program::id() {
+            return Err(LidoError::InvalidAccountInfo.into());
+        }
+
+        let data = vote_account.data.borrow();
+        let commission = get_vote_account_commission(&data)?;
+        if commission > max_commission {
+            return Err(LidoError::ValidationCommissionOutOfBounds.into());
+        }
+
+        let node_pubkey = Pubkey::new(&data[4..36]);
+
+        Ok(PartialVoteState {
+            node_pubkey,
+            commission,
+        })
+    }
+}
+
+/// Read the commission out of a vote account's raw data, without
+/// deserializing the full `VoteState`.
+///
+/// `VoteState`'s Borsh layout starts with a 4-byte enum version tag,
+/// followed by the 32-byte `node_pubkey`, and then the single-byte
+/// `commission`.
+pub fn get_vote_account_commission(data: &[u8]) -> Result<u8, ProgramError> {
+    data.get(36)
+        .copied()
+        .ok_or_else(|| LidoError::InvalidAccountInfo.into())
+}
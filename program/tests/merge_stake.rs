@@ -0,0 +1,390 @@
+#![cfg(feature = "test-bpf")]
+
+mod helpers;
+
+use helpers::{
+    program_test, simple_add_validator_to_pool,
+    stakepool_account::{transfer, ValidatorStakeAccount},
+    LidoAccounts,
+};
+use solana_program_test::{tokio, ProgramTestContext};
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::signature::Signer;
+use solana_sdk::transaction::TransactionError;
+
+/// Discriminant of `LidoError::InvalidStakeAccount`, which `ProgramError::Custom`
+/// surfaces as on the client side.
+const INVALID_STAKE_ACCOUNT: u32 = 7;
+
+async fn setup() -> (ProgramTestContext, LidoAccounts, ValidatorStakeAccount) {
+    let mut context = program_test().start_with_context().await;
+    let mut lido_accounts = LidoAccounts::new();
+    lido_accounts
+        .initialize_lido(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+        )
+        .await
+        .unwrap();
+
+    let validator_stake_account = simple_add_validator_to_pool(
+        &mut context.banks_client,
+        &context.payer,
+        &context.last_blockhash,
+        &lido_accounts,
+    )
+    .await;
+
+    (context, lido_accounts, validator_stake_account)
+}
+
+const DEPOSIT_AMOUNT: u64 = 100_000_000_000;
+
+/// After a merge, the old `begin` seed's derived address is deterministic.
+/// Re-funding that address must not let it be treated as a live stake
+/// account again: the protocol should reject it rather than "reviving" a
+/// stake account it believes no longer exists.
+///
+/// A third stake account is required for this test to mean anything: with
+/// only two accounts, `stake_seeds.end` is reached as soon as the first
+/// merge advances `begin`, so a second `merge_stake` call is rejected by the
+/// "fewer than two stake accounts" bounds check before it ever gets to
+/// compare addresses. With a third account, that bounds check passes, and
+/// the rejection this test cares about — the resurrected seed-0 account not
+/// matching what this instruction expects to find — is the one that fires.
+#[tokio::test]
+async fn test_refunding_old_seed_after_merge_is_rejected() {
+    let (mut context, lido_accounts, validator_account) = setup().await;
+
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    let first_stake = lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    context.warp_to_slot(50_000).unwrap();
+
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    // Warp again so the second and third stake accounts also fully activate:
+    // a fully active account cannot be merged with one still activating.
+    context.warp_to_slot(100_000).unwrap();
+
+    lido_accounts
+        .merge_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            0,
+            1,
+        )
+        .await
+        .unwrap();
+
+    // `first_stake` has now been merged away and ceases to exist. Fund it
+    // again at the same address, then try to merge it once more: the
+    // validator's `begin` seed has moved past it, and the re-funded address
+    // holds only lamports, not a stake delegation, so it is rejected as an
+    // invalid stake account rather than merged.
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &context.last_blockhash,
+        &first_stake,
+        DEPOSIT_AMOUNT,
+    )
+    .await;
+
+    let result = lido_accounts
+        .merge_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            0,
+            1,
+        )
+        .await;
+
+    match result.unwrap_err() {
+        solana_program_test::BanksClientError::TransactionError(
+            TransactionError::InstructionError(_, InstructionError::Custom(code)),
+        ) => assert_eq!(code, INVALID_STAKE_ACCOUNT),
+        other => panic!("expected a stake-account mismatch, got {:?}", other),
+    }
+}
+
+/// The second stake account here is still activating when the merge is
+/// attempted, while the first has fully activated: not a pair the stake
+/// program accepts, regardless of their differing `credits_observed`. The
+/// activation-state check in `process_merge_stake` rejects the pair before
+/// `check_stake_accounts_mergeable` or the merge CPI is reached, and
+/// `stake_seeds.begin` must be left untouched.
+#[tokio::test]
+async fn test_merge_rejects_mismatched_credits_observed() {
+    let (mut context, lido_accounts, validator_account) = setup().await;
+
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    // Warp far enough that the first stake account starts earning credits
+    // before the second one is even created, so the two end up with
+    // different `credits_observed`.
+    context.warp_to_slot(100_000).unwrap();
+
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    let validator_before = lido_accounts
+        .get_validator(&mut context.banks_client, &validator_account)
+        .await;
+
+    let result = lido_accounts
+        .merge_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            0,
+            1,
+        )
+        .await;
+    assert!(result.is_err());
+
+    let validator_after = lido_accounts
+        .get_validator(&mut context.banks_client, &validator_account)
+        .await;
+    assert_eq!(
+        validator_before.stake_seeds.begin,
+        validator_after.stake_seeds.begin
+    );
+}
+
+/// One account fully active and the other still activating cannot be
+/// merged; the processor must reject this pair rather than let the CPI
+/// fail, and must not advance `stake_seeds.begin`.
+#[tokio::test]
+async fn test_merge_rejects_activating_vs_active() {
+    let (mut context, lido_accounts, validator_account) = setup().await;
+
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    context.warp_to_slot(50_000).unwrap();
+
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    let validator_before = lido_accounts
+        .get_validator(&mut context.banks_client, &validator_account)
+        .await;
+
+    // Without warping another epoch, the second stake account is still
+    // activating while the first is fully active: not "merge-into-last",
+    // and not both fully active.
+    let result = lido_accounts
+        .merge_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            0,
+            1,
+        )
+        .await;
+    assert!(result.is_err());
+
+    let validator_after = lido_accounts
+        .get_validator(&mut context.banks_client, &validator_account)
+        .await;
+    assert_eq!(
+        validator_before.stake_seeds.begin,
+        validator_after.stake_seeds.begin
+    );
+}
+
+/// Once both stake accounts have fully activated, a difference in
+/// `credits_observed` is not a reason to refuse the merge: the stake program
+/// folds it into a stake-weighted average, same as it would for two
+/// accounts that happened to share the same value.
+#[tokio::test]
+async fn test_merge_allows_fully_active_with_different_credits_observed() {
+    let (mut context, lido_accounts, validator_account) = setup().await;
+
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    // Warp far enough that the first stake account starts earning credits
+    // before the second one is even created, so the two end up with
+    // different `credits_observed`.
+    context.warp_to_slot(50_000).unwrap();
+
+    lido_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+    lido_accounts
+        .stake_deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            DEPOSIT_AMOUNT,
+        )
+        .await;
+
+    // Warp again so the second stake account also fully activates before the
+    // merge is attempted.
+    context.warp_to_slot(100_000).unwrap();
+
+    let validator_before = lido_accounts
+        .get_validator(&mut context.banks_client, &validator_account)
+        .await;
+
+    lido_accounts
+        .merge_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &context.last_blockhash,
+            &validator_account,
+            0,
+            1,
+        )
+        .await
+        .unwrap();
+
+    let validator_after = lido_accounts
+        .get_validator(&mut context.banks_client, &validator_account)
+        .await;
+    assert_eq!(
+        validator_before.stake_seeds.begin + 1,
+        validator_after.stake_seeds.begin
+    );
+}